@@ -0,0 +1,8 @@
+//! Persistent per-user unlock daemon binary. See [`pam_keepassxc::daemon`].
+
+fn main() {
+	if let Err(err) = pam_keepassxc::daemon::run() {
+		eprintln!("pam_keepassxc-daemon: {err}");
+		std::process::exit(1);
+	}
+}
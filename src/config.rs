@@ -1,30 +1,175 @@
-use std::fs;
+use std::{fs, time::Duration};
 
 use nix::unistd::User;
 use serde::Deserialize;
 
 use crate::MODULE_NAME;
 
-#[derive(Deserialize)]
-pub struct UserConfig {
-	database_path: String,
+/// System-wide policy file, read before (and overlaid by) the user's file.
+const SYSTEM_CONFIG: &str = "/etc/security/pam_keepassxc.toml";
+
+/// Default allowlist of trusted KeePassXC service binaries. Flatpak, Snap,
+/// Nix and AUR installs live elsewhere and should add their path via
+/// `keepassxc_paths` in the config.
+const DEFAULT_KEEPASSXC_PATHS: &[&str] = &["/usr/bin/keepassxc", "/usr/local/bin/keepassxc"];
+/// Default deadline for the whole unlock handshake.
+const DEFAULT_TIMEOUT: u64 = 30;
+
+/// One database entry: a path plus an optional key file. Both are expanded
+/// through the same `$HOME`/`~` logic in [`expand`].
+#[derive(Clone, Deserialize)]
+pub struct DatabaseConfig {
+	pub database_path: String,
+	pub key_file: Option<String>,
+}
+
+/// A single file in the configuration stack. Every field is optional so the
+/// system and user layers can be merged key-by-key.
+#[derive(Default, Deserialize)]
+struct ConfigFile {
+	database_path: Option<String>,
+	key_file: Option<String>,
+	#[serde(default)]
+	databases: Vec<DatabaseConfig>,
+	timeout: Option<u64>,
+	/// Allowlist of trusted service-binary paths. The singular
+	/// `keepassxc_path` is accepted as a one-entry shorthand.
+	keepassxc_paths: Option<Vec<String>>,
+	keepassxc_path: Option<String>,
+	allow_session: Option<bool>,
+}
+
+impl ConfigFile {
+	fn read(path: &std::path::Path) -> Option<Self> {
+		basic_toml::from_str(&fs::read_to_string(path).ok()?).ok()
+	}
+
+	/// Overlay `other` on top of `self`, with `other`'s keys winning.
+	fn overlay(self, other: Self) -> Self {
+		// The inline `database_path`/`key_file` shorthand is one unit: if the
+		// user supplies their own `database_path`, take their `key_file` too
+		// (even when absent) rather than inheriting the system key file.
+		let (database_path, key_file) = if other.database_path.is_some() {
+			(other.database_path, other.key_file)
+		} else {
+			(self.database_path, self.key_file)
+		};
+		// The allowlist and its singular shorthand are one setting too: either
+		// key in the user's file replaces the system allowlist.
+		let (keepassxc_paths, keepassxc_path) =
+			if other.keepassxc_paths.is_some() || other.keepassxc_path.is_some() {
+				(other.keepassxc_paths, other.keepassxc_path)
+			} else {
+				(self.keepassxc_paths, self.keepassxc_path)
+			};
+
+		Self {
+			database_path,
+			key_file,
+			databases: if other.databases.is_empty() {
+				self.databases
+			} else {
+				other.databases
+			},
+			timeout: other.timeout.or(self.timeout),
+			keepassxc_paths,
+			keepassxc_path,
+			allow_session: other.allow_session.or(self.allow_session),
+		}
+	}
+}
+
+/// The resolved configuration after merging the system and user layers and
+/// filling in defaults. Totally populated, so callers never branch on a
+/// missing field.
+pub struct Config {
+	databases: Vec<DatabaseConfig>,
+	pub timeout: Duration,
+	pub keepassxc_paths: Vec<String>,
+	pub allow_session: bool,
 }
 
-pub fn user_config(user: &User) -> Option<UserConfig> {
-	let config_path = user
+impl Config {
+	/// Whether `path` is one of the trusted KeePassXC service binaries.
+	pub fn is_trusted_path(&self, path: &str) -> bool {
+		self.keepassxc_paths.iter().any(|trusted| trusted == path)
+	}
+}
+
+/// A database whose paths have been expanded against the user's home.
+pub struct Database {
+	pub database_path: String,
+	pub key_file: Option<String>,
+}
+
+impl Config {
+	/// Expand every configured database against the user's home directory,
+	/// flattening the inline shorthand and the explicit `[[databases]]` list
+	/// into one sequence.
+	pub fn databases(&self, user: &User) -> Vec<Database> {
+		self.databases
+			.iter()
+			.map(|db| Database {
+				database_path: expand(user, &db.database_path),
+				key_file: db.key_file.as_deref().map(|kf| expand(user, kf)),
+			})
+			.collect()
+	}
+}
+
+/// Load the layered configuration for `user`: read the system-wide policy,
+/// then overlay the user's file on top. Returns `None` only when neither
+/// layer can be read (a missing or unparsable file counts as absent), so a
+/// missing user file falls back cleanly to system policy
+/// rather than ignoring the module outright.
+pub fn load_config(user: &User) -> Option<Config> {
+	let user_path = user
 		.dir
 		.join(".config")
 		.join("security")
 		.join(MODULE_NAME)
 		.with_extension("toml");
 
-	basic_toml::from_str(&fs::read_to_string(config_path).ok()?).ok()
+	let system = ConfigFile::read(std::path::Path::new(SYSTEM_CONFIG));
+	let user_file = ConfigFile::read(&user_path);
+
+	let merged = match (system, user_file) {
+		(Some(system), Some(user_file)) => system.overlay(user_file),
+		(Some(only), None) | (None, Some(only)) => only,
+		(None, None) => return None,
+	};
+
+	// Fold the inline single-database shorthand into the list.
+	let mut databases = Vec::new();
+	if let Some(database_path) = merged.database_path {
+		databases.push(DatabaseConfig {
+			database_path,
+			key_file: merged.key_file,
+		});
+	}
+	databases.extend(merged.databases);
+
+	Some(Config {
+		databases,
+		timeout: Duration::from_secs(merged.timeout.unwrap_or(DEFAULT_TIMEOUT)),
+		keepassxc_paths: merged
+			.keepassxc_paths
+			.or_else(|| merged.keepassxc_path.map(|path| vec![path]))
+			.unwrap_or_else(|| {
+				DEFAULT_KEEPASSXC_PATHS
+					.iter()
+					.map(ToString::to_string)
+					.collect()
+			}),
+		allow_session: merged.allow_session.unwrap_or(true),
+	})
 }
 
-pub fn database_path(user: &User, config: &UserConfig) -> String {
+/// Expand `$HOME`/leading `~` in a configured path against the user's home.
+fn expand(user: &User, raw: &str) -> String {
 	let home_dir = user.dir.to_str().expect("");
 
-	let mut result = config.database_path.replace("$HOME", home_dir);
+	let mut result = raw.replace("$HOME", home_dir);
 	if result.starts_with('~') {
 		result.replace_range(0..1, home_dir);
 	}
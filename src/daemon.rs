@@ -0,0 +1,115 @@
+//! Persistent per-user unlock daemon.
+//!
+//! The daemon listens on a Unix socket under `/run/user/$UID/` and owns the
+//! whole D-Bus dance in [`crate::dbus`]. The PAM module is a thin client
+//! that ships an unlock request and returns immediately, so the wait for
+//! KeePassXC outlives the login transaction — useful when KeePassXC is autostarted
+//! slightly after login.
+
+use std::{
+	io,
+	os::unix::net::{UnixListener, UnixStream},
+	path::PathBuf,
+};
+
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use nix::unistd::{getuid, Uid, User};
+use secrecy::SecretString;
+
+use crate::{
+	config::load_config,
+	dbus::run_unlock,
+	protocol::{read_frame, write_frame, Request, Response},
+};
+
+/// Path of the daemon's listening socket for `uid`.
+pub fn socket_path(uid: Uid) -> PathBuf {
+	PathBuf::from(format!("/run/user/{uid}/{}.sock", crate::MODULE_NAME))
+}
+
+/// Run the daemon event loop. Binds the socket, replacing any stale one, and
+/// serves unlock requests until the process is signalled.
+pub fn run() -> Result<()> {
+	let uid = getuid();
+	let path = socket_path(uid);
+
+	// Replace a stale socket left by a previous daemon instance.
+	if path.exists() {
+		let _ = std::fs::remove_file(&path);
+	}
+	let listener = UnixListener::bind(&path)?;
+	info!("daemon listening on {}", path.display());
+
+	let user = User::from_uid(uid)?.ok_or_else(|| anyhow!("no passwd entry for uid {uid}"))?;
+
+	for stream in listener.incoming() {
+		match stream {
+			Ok(stream) => {
+				let user = user.clone();
+				if let Err(err) = handle(user, stream) {
+					error!("request failed: {err}");
+				}
+			}
+			Err(err) => warn!("accept failed: {err}"),
+		}
+	}
+
+	Ok(())
+}
+
+/// Handle a single client connection: acknowledge the request immediately,
+/// then run the unlock on a background thread so the client (and the PAM
+/// session it belongs to) need not wait for KeePassXC to appear.
+fn handle(user: User, mut stream: UnixStream) -> io::Result<()> {
+	let request: Request = read_frame(&mut stream)?;
+	write_frame(&mut stream, &Response::Success)?;
+
+	match request {
+		Request::Unlock {
+			database,
+			secret,
+			key_file,
+		} => {
+			let secret = SecretString::from(secret);
+			std::thread::spawn(move || {
+				let Some(config) = load_config(&user) else {
+					error!("no configuration for uid {}", user.uid);
+					return;
+				};
+				match run_unlock(&user, &config, &database, key_file.as_deref(), &secret) {
+					Ok(()) => info!("sent open request for {database}"),
+					Err(err) => error!("unlock failed: {err}"),
+				}
+			});
+		}
+	}
+
+	Ok(())
+}
+
+/// Client side: ship an unlock request to the running daemon and return as
+/// soon as it has acknowledged receipt.
+pub fn request_unlock(
+	user: &User,
+	database: &str,
+	key_file: Option<&str>,
+	pass: &SecretString,
+) -> Result<()> {
+	use secrecy::ExposeSecret;
+
+	let mut stream = UnixStream::connect(socket_path(user.uid))?;
+	write_frame(
+		&mut stream,
+		&Request::Unlock {
+			database: database.to_owned(),
+			secret: pass.expose_secret().to_owned(),
+			key_file: key_file.map(ToOwned::to_owned),
+		},
+	)?;
+
+	// The response is a receipt only; the daemon performs the unlock itself.
+	match read_frame::<_, Response>(&mut stream)? {
+		Response::Success => Ok(()),
+	}
+}
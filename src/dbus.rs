@@ -4,6 +4,7 @@ use std::{
 };
 
 use anyhow::{anyhow, Result};
+use log::{error, info};
 use nix::{sys::socket::UnixAddr, unistd::User};
 use rustbus::{
 	connection::Timeout,
@@ -12,12 +13,13 @@ use rustbus::{
 };
 use secrecy::{ExposeSecret, SecretString};
 
-use crate::config::{database_path, UserConfig};
+use crate::config::Config;
 
 const KEEPASSXC_DBUS_NAME: &str = "org.keepassxc.KeePassXC.MainWindow";
 
-const TIMEOUT: Duration = Duration::from_secs(30);
-const INTERVAL: Duration = Duration::from_secs(1);
+/// Backoff between attempts to connect to the session bus socket, which may
+/// not exist yet when the fallback runs at `authenticate` time.
+const CONNECT_RETRY: Duration = Duration::from_millis(250);
 
 pub fn user_session_bus(user: &User) -> Result<UnixAddr> {
 	Ok(UnixAddr::new(
@@ -25,47 +27,162 @@ pub fn user_session_bus(user: &User) -> Result<UnixAddr> {
 	)?)
 }
 
-pub fn wait_for_dbus(user: &User) -> Result<RpcConn> {
+/// Wait for KeePassXC to own its well-known bus name.
+///
+/// The session bus socket is polled until it appears (it may not exist yet
+/// at authenticate time), then — event-driven — we connect once, subscribe
+/// to `NameOwnerChanged` filtered to the KeePassXC name, and block on
+/// incoming signals until the name acquires an owner (or the deadline
+/// elapses). If KeePassXC already owns the name at subscription time we
+/// proceed immediately, so there is no added latency.
+pub fn wait_for_dbus(user: &User, config: &Config) -> Result<RpcConn> {
 	let socket_addr = user_session_bus(user)?;
 
-	let start = Instant::now();
-	let conn = loop {
-		if let Ok(conn) = RpcConn::connect_to_path(socket_addr, Timeout::Duration(TIMEOUT)) {
-			break conn;
-		}
+	// Track a single absolute deadline across both the connect retry and the
+	// signal wait, so the total wait stays bounded by `timeout`.
+	let deadline = Instant::now() + config.timeout;
 
-		if start.elapsed() >= TIMEOUT {
-			return Err(anyhow!("Timed out."));
+	// The session bus socket may not exist yet when the fallback runs at
+	// authenticate time — pam_systemd creates it during the session phase —
+	// so retry the connect until it appears or the deadline elapses.
+	let mut conn = loop {
+		let remaining = remaining_until(deadline)?;
+		match RpcConn::connect_to_path(socket_addr, Timeout::Duration(remaining)) {
+			Ok(conn) => break conn,
+			Err(_) => {
+				remaining_until(deadline)?;
+				sleep(CONNECT_RETRY);
+			}
 		}
-		sleep(INTERVAL);
 	};
+	conn.set_filter(Box::new(|msg| {
+		matches!(msg.typ, rustbus::message_builder::MessageType::Signal)
+	}));
+
+	// Subscribe before checking current ownership so the transition can't slip
+	// through the gap between the two calls.
+	add_owner_match(&mut conn, remaining_until(deadline)?)?;
+
+	if name_has_owner(&mut conn, remaining_until(deadline)?)? {
+		return Ok(conn);
+	}
+
+	loop {
+		let remaining = remaining_until(deadline)?;
+		let signal = conn.wait_signal(Timeout::Duration(remaining))?;
+		if signal.dynheader.member.as_deref() != Some("NameOwnerChanged") {
+			continue;
+		}
+
+		let mut parser = signal.body.parser();
+		let name: String = parser.get()?;
+		let _old_owner: String = parser.get()?;
+		let new_owner: String = parser.get()?;
+		if name == KEEPASSXC_DBUS_NAME && !new_owner.is_empty() {
+			return Ok(conn);
+		}
+	}
+}
 
-	Ok(conn)
+/// Time left until `deadline`, or a timeout error once it has passed.
+fn remaining_until(deadline: Instant) -> Result<Duration> {
+	deadline
+		.checked_duration_since(Instant::now())
+		.ok_or_else(|| anyhow!("Timed out."))
 }
 
-pub fn try_unlock(
-	flag: bool,
-	user: &User,
-	user_config: &UserConfig,
-	pass: &SecretString,
-) -> Result<()> {
+/// Install a bus match rule for `NameOwnerChanged` on the KeePassXC name.
+fn add_owner_match(conn: &mut RpcConn, timeout: Duration) -> Result<()> {
+	let rule = format!(
+		"type='signal',sender='org.freedesktop.DBus',\
+		 interface='org.freedesktop.DBus',member='NameOwnerChanged',arg0='{KEEPASSXC_DBUS_NAME}'"
+	);
+
+	let mut call = MessageBuilder::new()
+		.call("AddMatch")
+		.with_interface("org.freedesktop.DBus")
+		.on("/org/freedesktop/DBus")
+		.at("org.freedesktop.DBus")
+		.build();
+	call.body.push_param(rule.as_str())?;
+
+	let id = conn.send_message(&mut call)?.write_all().map_err(|e| e.1)?;
+	let _ = conn.wait_response(id, Timeout::Duration(timeout))?;
+
+	Ok(())
+}
+
+/// Whether the KeePassXC name already has an owner on the bus.
+fn name_has_owner(conn: &mut RpcConn, timeout: Duration) -> Result<bool> {
+	let mut call = MessageBuilder::new()
+		.call("GetNameOwner")
+		.with_interface("org.freedesktop.DBus")
+		.on("/org/freedesktop/DBus")
+		.at("org.freedesktop.DBus")
+		.build();
+	call.body.push_param(KEEPASSXC_DBUS_NAME)?;
+
+	let id = conn.send_message(&mut call)?.write_all().map_err(|e| e.1)?;
+	let reply = conn.wait_response(id, Timeout::Duration(timeout))?;
+
+	// A `NameHasNoOwner` error simply means it isn't running yet.
+	Ok(reply.dynheader.error_name.is_none())
+}
+
+pub fn try_unlock(flag: bool, user: &User, config: &Config, pass: &SecretString) -> Result<()> {
 	let mut conn = if flag {
-		RpcConn::connect_to_path(user_session_bus(user)?, Timeout::Duration(TIMEOUT))?
+		RpcConn::connect_to_path(user_session_bus(user)?, Timeout::Duration(config.timeout))?
 	} else {
-		wait_for_dbus(user)?
+		wait_for_dbus(user, config)?
 	};
 
-	activate(&mut conn)?;
+	activate(&mut conn, config.timeout)?;
+
+	let pid = get_pid(&mut conn, config.timeout)?;
+	verify(&mut conn, config, user, pid)?;
+
+	// Send an open request for every configured database with the same login
+	// password. `openDatabase` is fire-and-forget (no reply is read), so this
+	// logs that the request was sent, not that the database was unlocked — a
+	// wrong password or unusable key file is handled silently by KeePassXC. An
+	// error building the request for one database is logged and does not abort
+	// the rest; `unlock` discards the result of writing it to the bus.
+	for db in config.databases(user) {
+		match unlock(&mut conn, &db.database_path, db.key_file.as_deref(), pass) {
+			Ok(()) => info!("sent open request for {}", db.database_path),
+			Err(err) => error!("failed to send open request for {}: {err}", db.database_path),
+		}
+	}
+	Ok(())
+}
+
+/// Unlock a single database once KeePassXC has registered on the bus,
+/// waiting for it until the configured deadline elapses. The handshake and
+/// the open request are made once; a failure is returned, not retried.
+///
+/// Used by the daemon, which owns the D-Bus dance outside the PAM session
+/// and can therefore afford to keep waiting after PAM hands control back to
+/// the session manager.
+pub fn run_unlock(
+	user: &User,
+	config: &Config,
+	database: &str,
+	key_file: Option<&str>,
+	pass: &SecretString,
+) -> Result<()> {
+	// wait_for_dbus blocks until KeePassXC owns the name, so the handshake
+	// below runs exactly once — no retry loop needed.
+	let mut conn = wait_for_dbus(user, config)?;
 
-	let pid = get_pid(&mut conn)?;
-	verify(&mut conn, pid)?;
+	activate(&mut conn, config.timeout)?;
+	let pid = get_pid(&mut conn, config.timeout)?;
+	verify(&mut conn, config, user, pid)?;
 
-	let database_path = database_path(user, user_config);
-	unlock(&mut conn, &database_path, pass)?;
+	unlock(&mut conn, database, key_file, pass)?;
 	Ok(())
 }
 
-fn activate(conn: &mut RpcConn) -> Result<()> {
+fn activate(conn: &mut RpcConn, timeout: Duration) -> Result<()> {
 	let mut call = MessageBuilder::new()
 		.call("Ping")
 		.with_interface("org.freedesktop.DBus.Peer")
@@ -77,12 +194,12 @@ fn activate(conn: &mut RpcConn) -> Result<()> {
 		.send_message(&mut call)?
 		.write_all()
 		.map_err(|err| err.1)?;
-	let _ = conn.wait_response(id, Timeout::Duration(TIMEOUT))?;
+	let _ = conn.wait_response(id, Timeout::Duration(timeout))?;
 
 	Ok(())
 }
 
-fn get_pid(conn: &mut RpcConn) -> Result<u32> {
+fn get_pid(conn: &mut RpcConn, timeout: Duration) -> Result<u32> {
 	// Get PID of KeePassXC service.
 	let mut call = MessageBuilder::new()
 		.call("GetConnectionUnixProcessID")
@@ -93,13 +210,13 @@ fn get_pid(conn: &mut RpcConn) -> Result<u32> {
 	call.body.push_param(KEEPASSXC_DBUS_NAME)?;
 
 	let id = conn.send_message(&mut call)?.write_all().map_err(|e| e.1)?;
-	let message = conn.wait_response(id, Timeout::Duration(TIMEOUT))?;
+	let message = conn.wait_response(id, Timeout::Duration(timeout))?;
 
 	let pid: u32 = message.body.parser().get()?;
 	Ok(pid)
 }
 
-fn verify(conn: &mut RpcConn, pid: u32) -> Result<()> {
+fn verify(conn: &mut RpcConn, config: &Config, user: &User, pid: u32) -> Result<()> {
 	let mut call = MessageBuilder::new()
 		.call("GetUnitByPID")
 		.with_interface("org.freedesktop.systemd1.Manager")
@@ -109,17 +226,19 @@ fn verify(conn: &mut RpcConn, pid: u32) -> Result<()> {
 	call.body.push_param(pid)?;
 
 	let id = conn.send_message(&mut call)?.write_all().map_err(|e| e.1)?;
-	let reply = conn.wait_response(id, Timeout::Duration(TIMEOUT))?;
+	let reply = conn.wait_response(id, Timeout::Duration(config.timeout))?;
 	if let Some(err) = reply.dynheader.error_name {
 		return Err(anyhow!(err));
 	}
 
 	let service_object: ObjectPath<&str> = reply.body.parser().get()?;
+	let service_object = service_object.as_ref().to_owned();
 
+	// Check the trusted-binary allowlist against the unit's ExecStart.
 	let mut call = MessageBuilder::new()
 		.call("Get")
 		.with_interface("org.freedesktop.DBus.Properties")
-		.on(service_object.as_ref())
+		.on(service_object.as_str())
 		.at("org.freedesktop.systemd1")
 		.build();
 	call.body
@@ -129,27 +248,68 @@ fn verify(conn: &mut RpcConn, pid: u32) -> Result<()> {
 		.send_message(&mut call)?
 		.write_all()
 		.map_err(|err| err.1)?;
-	let reply = conn.wait_response(id, Timeout::Duration(TIMEOUT))?;
+	let reply = conn.wait_response(id, Timeout::Duration(config.timeout))?;
 
 	let exec: Vec<SystemdExec> = reply.body.parser().get::<Variant>()?.get()?;
 	let exec = exec.first().ok_or(anyhow!(""))?;
 
-	if exec.path == "/usr/bin/keepassxc" && exec.pid == pid {
-		Ok(())
-	} else {
-		Err(anyhow!("Invalid keepassxc service"))
+	if !config.is_trusted_path(&exec.path) || exec.pid != pid {
+		return Err(anyhow!("Untrusted keepassxc service binary: {}", exec.path));
+	}
+
+	// Confirm the unit actually lives in the authenticating user's systemd
+	// slice, so another user's process can't claim the MainWindow name and be
+	// handed the password. The per-user manager parents everything under
+	// `/user.slice/user-<uid>.slice/user@<uid>.service`.
+	let mut call = MessageBuilder::new()
+		.call("Get")
+		.with_interface("org.freedesktop.DBus.Properties")
+		.on(service_object.as_str())
+		.at("org.freedesktop.systemd1")
+		.build();
+	call.body
+		.push_param2("org.freedesktop.systemd1.Unit", "ControlGroup")?;
+
+	let id = conn
+		.send_message(&mut call)?
+		.write_all()
+		.map_err(|err| err.1)?;
+	let reply = conn.wait_response(id, Timeout::Duration(config.timeout))?;
+
+	let control_group: String = reply.body.parser().get::<Variant>()?.get()?;
+	let expected = format!("/user.slice/user-{0}.slice/user@{0}.service", user.uid);
+	if !control_group.starts_with(&expected) {
+		return Err(anyhow!(
+			"keepassxc cgroup {control_group} is not under {expected}"
+		));
 	}
+
+	Ok(())
 }
 
-fn unlock(conn: &mut RpcConn, database: &str, pass: &SecretString) -> Result<()> {
+fn unlock(
+	conn: &mut RpcConn,
+	database: &str,
+	key_file: Option<&str>,
+	pass: &SecretString,
+) -> Result<()> {
 	// Build a D-Bus message to request the unlocking of the KeePassXC database.
+	// `openDatabase` takes (path, password, keyFile); an empty key-file string
+	// means "password only".
+	//
+	// Note: a key file backed by a hardware-token challenge-response slot
+	// cannot be satisfied over this call — KeePassXC would need to prompt the
+	// token interactively. The call is fire-and-forget (no reply is read), so
+	// such a database simply stays locked; there is no signal here to turn it
+	// into a distinct skip.
 	let mut call = MessageBuilder::new()
 		.call("openDatabase")
 		.with_interface("org.keepassxc.KeePassXC.MainWindow")
 		.on("/keepassxc")
 		.at(KEEPASSXC_DBUS_NAME)
 		.build();
-	call.body.push_param2(database, pass.expose_secret())?;
+	call.body
+		.push_param3(database, pass.expose_secret(), key_file.unwrap_or(""))?;
 
 	let _ = conn.send_message(&mut call)?.write_all();
 
@@ -0,0 +1,123 @@
+//! Privilege-separated unlock helper.
+//!
+//! Instead of stashing the login password in retrievable PAM data and
+//! `double_fork`ing later, `authenticate` spawns a small privileged
+//! *monitor* process that keeps the [`SecretString`] in `mlock`'d memory.
+//! The monitor forks one unprivileged *worker* that drops to the target
+//! uid/gid with `setresuid`/`setresgid` before it ever touches the session
+//! bus, and only then unlocks.
+//!
+//! This gives a single auditable place where privileges are dropped and
+//! removes the window where the cleartext password lives in PAM module
+//! memory across the whole session negotiation. The secret reaches the
+//! worker through `fork` alone — it is never written to a socket.
+
+use anyhow::{anyhow, Result};
+use log::{error, warn};
+use nix::{
+	sys::{
+		mman::{mlock, munlock},
+		wait::{waitpid, WaitStatus},
+	},
+	unistd::{close, fork, setsid, ForkResult, Gid, Uid},
+};
+use secrecy::{ExposeSecret, SecretString};
+
+use crate::{config::load_config, dbus::try_unlock, init_syslog};
+
+/// The identity the worker must assume before it may unlock. The worker
+/// re-reads the user's configuration itself once it has dropped privileges,
+/// so the spec only carries the target uid/gid.
+struct SessionSpec {
+	uid: Uid,
+	gid: Gid,
+}
+
+/// Spawn the privileged monitor for this authentication.
+///
+/// The monitor inherits the `SecretString` through `fork` and immediately
+/// detaches; the PAM side returns as soon as the monitor has been forked.
+pub fn spawn_monitor(user: &nix::unistd::User, pass: SecretString) -> Result<()> {
+	let spec = SessionSpec {
+		uid: user.uid,
+		gid: user.gid,
+	};
+
+	match unsafe { fork() } {
+		Ok(ForkResult::Parent { child }) => {
+			warn!("Spawned unlock monitor with PID: {child}");
+			Ok(())
+		}
+		Ok(ForkResult::Child) => {
+			let _ = setsid();
+			let _ = close(0);
+			let _ = close(1);
+			let _ = close(2);
+			let _ = init_syslog(); // Reinitialize syslog for the monitor PID.
+
+			run_monitor(&spec, pass);
+			std::process::exit(0)
+		}
+		Err(err) => Err(anyhow!("{err}")),
+	}
+}
+
+/// Monitor entry point: pin the secret in memory, fork the worker, and log
+/// its exit status via syslog.
+fn run_monitor(spec: &SessionSpec, pass: SecretString) {
+	// Pin the secret so it never reaches swap while the monitor lives.
+	let secret = pass.expose_secret().as_bytes();
+	let locked = unsafe { mlock(secret.as_ptr().cast(), secret.len()) }.is_ok();
+
+	if let Err(err) = serve_worker(spec, &pass) {
+		error!("Monitor: unlock failed: {err}");
+	}
+
+	if locked {
+		let _ = unsafe { munlock(secret.as_ptr().cast(), secret.len()) };
+	}
+}
+
+/// Fork the unprivileged worker and wait for it to finish, mapping a
+/// non-zero exit into an error for the monitor to log.
+fn serve_worker(spec: &SessionSpec, pass: &SecretString) -> Result<()> {
+	match unsafe { fork() } {
+		Ok(ForkResult::Parent { child }) => match waitpid(child, None)? {
+			WaitStatus::Exited(_, 0) => {
+				warn!("Worker sent open requests for uid {}", spec.uid);
+				Ok(())
+			}
+			status => Err(anyhow!("worker failed: {status:?}")),
+		},
+		Ok(ForkResult::Child) => std::process::exit(i32::from(run_worker(spec, pass))),
+		Err(err) => Err(anyhow!("{err}")),
+	}
+}
+
+/// Worker body: drop privileges *before* touching the session bus, then
+/// unlock with the secret inherited from the monitor via `fork`.
+fn run_worker(spec: &SessionSpec, pass: &SecretString) -> u8 {
+	use nix::unistd::{setresgid, setresuid, User};
+
+	if setresgid(spec.gid, spec.gid, spec.gid).is_err()
+		|| setresuid(spec.uid, spec.uid, spec.uid).is_err()
+	{
+		return 1;
+	}
+
+	let user = match User::from_uid(spec.uid) {
+		Ok(Some(user)) => user,
+		_ => return 1,
+	};
+	let Some(cfg) = load_config(&user) else {
+		return 1;
+	};
+
+	match try_unlock(false, &user, &cfg, pass) {
+		Ok(()) => 0,
+		Err(err) => {
+			error!("{err}");
+			1
+		}
+	}
+}
@@ -0,0 +1,60 @@
+//! Framed IPC protocol between the PAM module (client) and the per-user
+//! daemon (server).
+//!
+//! Each message is a 4-byte native-endian `u32` length header followed by a
+//! JSON body. The request/response variants are tagged by a `type` field:
+//!
+//! ```text
+//! {"type":"unlock","database":"…","secret":"…"}
+//! {"type":"success"}
+//! ```
+//!
+//! The daemon acknowledges receipt and then performs the unlock on its own,
+//! outliving the PAM session, so the response is a receipt only — it does
+//! not report the eventual unlock outcome.
+
+use std::io::{self, Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+/// A request from the PAM client to the daemon.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+	/// Unlock `database` with `secret` (and optional `key_file`), waiting
+	/// up to the configured timeout for KeePassXC to appear.
+	Unlock {
+		database: String,
+		secret: String,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		key_file: Option<String>,
+	},
+}
+
+/// The daemon's reply to a [`Request`]. This is a receipt: it confirms the
+/// daemon accepted the request, not that the unlock succeeded.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+	Success,
+}
+
+/// Write a value as a length-prefixed JSON frame.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, value: &T) -> io::Result<()> {
+	let body = serde_json::to_vec(value)?;
+	let len = u32::try_from(body.len())
+		.map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "frame too large"))?;
+	writer.write_all(&len.to_ne_bytes())?;
+	writer.write_all(&body)?;
+	writer.flush()
+}
+
+/// Read one length-prefixed JSON frame and deserialize it.
+pub fn read_frame<R: Read, T: for<'de> Deserialize<'de>>(reader: &mut R) -> io::Result<T> {
+	let mut header = [0u8; 4];
+	reader.read_exact(&mut header)?;
+	let len = u32::from_ne_bytes(header) as usize;
+	let mut body = vec![0u8; len];
+	reader.read_exact(&mut body)?;
+	serde_json::from_slice(&body).map_err(Into::into)
+}